@@ -8,57 +8,220 @@
 //! while minimizing external process calls.
 use error::ErrorKind as Ek;
 use failure::Fallible as Result;
+use serde::{Deserialize, Serialize};
 use status::TaskStatus;
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
     collections::HashMap,
+    fs::File,
     iter::once,
+    ops::{Deref, DerefMut},
+    path::Path,
+    time::{Duration, Instant},
 };
 
 use task::Task;
 use tw::{query, save};
 use uuid::Uuid;
+use zstd::stream::{Decoder, Encoder};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// Default zstd compression level used by `TaskCache::save_to_path`.
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 enum MutationState {
     Dirty,
     Clean,
 }
 
+/// A stable content hash of a Task's canonical JSON serialization, used to tell whether a Task
+/// borrowed mutably was actually changed before spending a `task modify` call on it.
+type TaskHash = [u8; 32];
+
+fn hash_task(task: &Task) -> Result<TaskHash> {
+    serde_json::to_vec(task)
+        .map(|bytes| *blake3::hash(&bytes).as_bytes())
+        .map_err(|_| Ek::SerializeError.into())
+}
+
+/// Whether a cell whose freshly computed content hash is `new_hash` needs saving, given the
+/// `baseline` hash it was last saved (or loaded) with. `None` means there is no baseline yet
+/// (e.g. a freshly `set` task), which is always treated as changed.
+fn should_update(new_hash: TaskHash, baseline: Option<TaskHash>) -> bool {
+    baseline != Some(new_hash)
+}
+
 /// A TaskCache caches tasks.
 /// For performance reasons a TaskCache can blacklist
 /// That means tasks in that state won't be requested from taskwarrior.
 /// This will give a performance advantage, when ignoring completed and deleted tasks.
 /// Note, that when the program makes changes on the cache there still might be tasks in ignored
 /// states in the cache. They will be saved on calling `write()` regardless of their new state.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct TaskCache {
-    cache: HashMap<Uuid, RefCell<(Task, MutationState)>>,
+    cache: HashMap<Uuid, RefCell<(Task, MutationState, Option<TaskHash>)>>,
     ignore: Vec<TaskStatus>,
+    autoflush: Option<AutoFlushPolicy>,
+    dirty_since: Cell<Option<Instant>>,
+}
+
+/// Two TaskCaches are equal if they cache the same tasks in the same state; the `AutoFlushPolicy`
+/// and the bookkeeping of how long they have been dirty are just scheduling details, not part of
+/// the cache's observable content.
+impl PartialEq for TaskCache {
+    fn eq(&self, other: &Self) -> bool {
+        self.cache == other.cache && self.ignore == other.ignore
+    }
 }
 
 /// A TaskCell contains a pointer to a Task in the cache. Which can be borrow immutable or mutable.MutationState
 /// The calls will return None if a conflicting Borrow is active.
 pub struct TaskCell<'a> {
-    cell: &'a RefCell<(Task, MutationState)>,
+    cell: &'a RefCell<(Task, MutationState, Option<TaskHash>)>,
     cache: &'a TaskCache,
 }
 
+/// A mutable handle to a cached Task, returned by `TaskCell::borrow_mut`.
+/// Derefs to the Task; when dropped (i.e. once the caller is done mutating it), it gives the
+/// cache's `AutoFlushPolicy`, if any, a chance to opportunistically flush dirty tasks.
+pub struct TaskCellMut<'a> {
+    guard: Option<RefMut<'a, Task>>,
+    cache: &'a TaskCache,
+}
+
+impl<'a> Deref for TaskCellMut<'a> {
+    type Target = Task;
+    fn deref(&self) -> &Task {
+        self.guard.as_ref().expect("guard is only taken on drop")
+    }
+}
+
+impl<'a> DerefMut for TaskCellMut<'a> {
+    fn deref_mut(&mut self) -> &mut Task {
+        self.guard.as_mut().expect("guard is only taken on drop")
+    }
+}
+
+impl<'a> Drop for TaskCellMut<'a> {
+    fn drop(&mut self) {
+        // Release the borrow on this cell before potentially flushing, since a flush re-borrows
+        // every dirty cell, including this one.
+        self.guard.take();
+        let _ = self.cache.maybe_autoflush();
+    }
+}
+
+/// Configures when a TaskCache should opportunistically flush dirty tasks on its own, instead of
+/// only flushing when the user explicitly calls `write()`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct AutoFlushPolicy {
+    max_dirty: Option<usize>,
+    max_elapsed: Option<Duration>,
+}
+
+impl AutoFlushPolicy {
+    /// Creates a policy that never auto-flushes until configured with `max_dirty`/`max_elapsed`.
+    pub fn new() -> AutoFlushPolicy {
+        AutoFlushPolicy::default()
+    }
+
+    /// Flush once at least this many tasks are dirty.
+    pub fn max_dirty(mut self, max_dirty: usize) -> AutoFlushPolicy {
+        self.max_dirty = Some(max_dirty);
+        self
+    }
+
+    /// Flush once the oldest pending dirty task has been dirty for at least this long.
+    pub fn max_elapsed(mut self, max_elapsed: Duration) -> AutoFlushPolicy {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+}
+
+/// Whether a cache with `dirty` pending tasks, the oldest of which has been dirty for `elapsed`,
+/// should flush under `policy`. There is never anything to flush if nothing is dirty.
+fn should_autoflush(policy: AutoFlushPolicy, dirty: usize, elapsed: Duration) -> bool {
+    if dirty == 0 {
+        return false;
+    }
+    let over_count = policy.max_dirty.map(|max| dirty >= max).unwrap_or(false);
+    let over_time = policy.max_elapsed.map(|max| elapsed >= max).unwrap_or(false);
+    over_count || over_time
+}
+
+/// A composable filter to select a subset of the tasks in a TaskCache.
+/// Build one with `TaskFilter::new()` and the `filter_*` builder methods, then pass it to
+/// `TaskCache::query`. An empty filter matches every task.
+#[derive(Default)]
+pub struct TaskFilter {
+    status: Option<Vec<TaskStatus>>,
+    predicate: Option<Box<dyn Fn(&Task) -> bool>>,
+}
+
+impl TaskFilter {
+    /// Creates a new, empty TaskFilter matching every task.
+    pub fn new() -> TaskFilter {
+        TaskFilter::default()
+    }
+
+    /// Restricts the filter to tasks whose status is one of `status`.
+    pub fn filter_status(mut self, status: Vec<TaskStatus>) -> TaskFilter {
+        self.status = Some(status);
+        self
+    }
+
+    /// Restricts the filter to tasks belonging to `project`.
+    pub fn filter_project(self, project: String) -> TaskFilter {
+        self.filter_fn(move |task| task.project().map(|p| p == &project).unwrap_or(false))
+    }
+
+    /// Restricts the filter to tasks matching an arbitrary predicate.
+    /// Combines with a previously set predicate by requiring both to match.
+    pub fn filter_fn<F>(mut self, predicate: F) -> TaskFilter
+    where
+        F: Fn(&Task) -> bool + 'static,
+    {
+        self.predicate = Some(match self.predicate.take() {
+            Some(previous) => Box::new(move |task| previous(task) && predicate(task)),
+            None => Box::new(predicate),
+        });
+        self
+    }
+
+    fn matches(&self, task: &Task) -> bool {
+        let status_matches = self
+            .status
+            .as_ref()
+            .map(|status| status.contains(task.status()))
+            .unwrap_or(true);
+        let predicate_matches = self.predicate.as_ref().map(|p| p(task)).unwrap_or(true);
+        status_matches && predicate_matches
+    }
+}
+
 impl<'a> TaskCell<'a> {
     /// Trys to borrow the Task immutable.
     pub fn borrow(&self) -> Option<Ref<Task>> {
         self.cell
             .try_borrow()
             .ok()
-            .map(|x| Ref::map(x, |(task, _)| task))
+            .map(|x| Ref::map(x, |(task, _, _)| task))
     }
     /// Trys to borrow the Task mutable.
-    pub fn borrow_mut(&self) -> Option<RefMut<Task>> {
+    /// This marks the cell as a candidate for saving; whether it actually gets saved is decided
+    /// at `write()` time by comparing content hashes, so borrowing mutably without changing
+    /// anything does not cause a spurious `task modify` call. Dropping the returned handle gives
+    /// the cache's `AutoFlushPolicy`, if any, a chance to opportunistically flush.
+    pub fn borrow_mut(&self) -> Option<TaskCellMut> {
         self.cell.try_borrow_mut().ok().map(|x| {
-            RefMut::map(x, |(task, state)| {
+            let guard = RefMut::map(x, |(task, state, _)| {
                 *state = MutationState::Dirty;
                 task
-            })
+            });
+            TaskCellMut {
+                guard: Some(guard),
+                cache: self.cache,
+            }
         })
     }
 
@@ -76,8 +239,12 @@ fn generate_query(ignore: &[TaskStatus]) -> String {
         .join(" ")
 }
 
-fn task_to_entry(task: Task) -> (Uuid, RefCell<(Task, MutationState)>) {
-    (*task.uuid(), RefCell::new((task, MutationState::Clean)))
+fn task_to_entry(task: Task) -> Result<(Uuid, RefCell<(Task, MutationState, Option<TaskHash>)>)> {
+    let hash = hash_task(&task)?;
+    Ok((
+        *task.uuid(),
+        RefCell::new((task, MutationState::Clean, Some(hash))),
+    ))
 }
 
 impl TaskCache {
@@ -86,14 +253,57 @@ impl TaskCache {
         TaskCache {
             cache: HashMap::new(),
             ignore: ignore,
+            autoflush: None,
+            dirty_since: Cell::new(None),
         }
     }
 
+    /// Configures an `AutoFlushPolicy` so that `set` and mutable borrows opportunistically flush
+    /// dirty tasks once a threshold is crossed, instead of only flushing on an explicit `write()`.
+    pub fn with_autoflush(mut self, policy: AutoFlushPolicy) -> TaskCache {
+        self.autoflush = Some(policy);
+        self
+    }
+
     /// Gives tasks ignored by this TaskCache
     pub fn ignore(&self) -> &Vec<TaskStatus> {
         &self.ignore
     }
 
+    /// The number of tasks currently marked dirty and awaiting a `write()`.
+    /// A cell currently borrowed elsewhere (e.g. via a live `TaskCellMut`) is conservatively
+    /// counted as dirty, since `borrow_mut` always marks a cell dirty before handing out such a
+    /// borrow.
+    pub fn pending_dirty(&self) -> usize {
+        self.cache
+            .values()
+            .filter(|x| {
+                x.try_borrow()
+                    .map(|entry| entry.1 == MutationState::Dirty)
+                    .unwrap_or(true)
+            })
+            .count()
+    }
+
+    /// Checks the configured `AutoFlushPolicy`, if any, and flushes all dirty tasks in a single
+    /// batched `write()` once the dirty count or elapsed time threshold is crossed.
+    fn maybe_autoflush(&self) -> Result<()> {
+        let policy = match self.autoflush {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let dirty_since = self.dirty_since.get().unwrap_or(now);
+        self.dirty_since.set(Some(dirty_since));
+
+        let dirty = self.pending_dirty();
+        if should_autoflush(policy, dirty, now.duration_since(dirty_since)) {
+            // `write()` itself resets `dirty_since` once the cache is fully clean again.
+            self.write()?;
+        }
+        Ok(())
+    }
+
     /// Will load all unignored tasks in the cache.
     /// This will throw an error of kind DirtyCacheError, if there are unsaved changes.
     /// Call `reset` first to circumvent this if you need it.
@@ -107,9 +317,12 @@ impl TaskCache {
         } else {
             self.cache.clear();
         }
-        query(&generate_query(&self.ignore))
-            .map(|x| x.into_iter().map(task_to_entry))
-            .map(|x| self.cache.extend(x))
+        let tasks = query(&generate_query(&self.ignore))?;
+        for task in tasks {
+            let (uuid, entry) = task_to_entry(task)?;
+            self.cache.insert(uuid, entry);
+        }
+        Ok(())
     }
 
     /// Clears the cache and throws away unsaved changes.
@@ -124,19 +337,47 @@ impl TaskCache {
         self.write().and_then(|_| self.load())
     }
 
-    /// Saves all entries marked as dirty.
-    pub fn write(&mut self) -> Result<()> {
+    /// Saves all entries whose content actually changed since they were loaded or last written.
+    /// Cells are only considered candidates once they have been marked dirty by `borrow_mut`;
+    /// among those, only the ones whose content hash differs from the stored baseline (or that
+    /// have no baseline yet, e.g. freshly `set`) are sent to taskwarrior, which avoids spurious
+    /// `task modify` calls on no-op mutable borrows.
+    /// `write()` can be re-entered while other `TaskCell`/`TaskCellMut` borrows on *other* cells
+    /// are still alive (e.g. via an `AutoFlushPolicy` triggered on drop), so it uses `try_borrow`
+    /// and simply leaves a currently-borrowed cell dirty for a later `write()` instead of
+    /// panicking.
+    pub fn write(&self) -> Result<()> {
+        let mut changed = Vec::new();
+        for (uuid, cell) in &self.cache {
+            let new_hash = match cell.try_borrow() {
+                Ok(entry) if entry.1 == MutationState::Dirty => hash_task(&entry.0)?,
+                Ok(_) | Err(_) => continue,
+            };
+            let mut entry = match cell.try_borrow_mut() {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            entry.1 = MutationState::Clean;
+            if should_update(new_hash, entry.2) {
+                entry.2 = Some(new_hash);
+                changed.push(*uuid);
+            }
+        }
         let updates = self
             .cache
-            .values()
-            .map(RefCell::borrow)
-            .filter(|x| (*x).1 == MutationState::Dirty)
+            .iter()
+            .filter(|(uuid, _)| changed.contains(uuid))
+            .filter_map(|(_, cell)| cell.try_borrow().ok())
             .collect::<Vec<_>>();
-        if updates.is_empty() {
+        let result = if updates.is_empty() {
             Ok(())
         } else {
             save(updates.iter().map(|x| &(*x).0))
+        };
+        if self.pending_dirty() == 0 {
+            self.dirty_since.set(None);
         }
+        result
     }
 
     /// Gives an Iterator over all tasks in the cache
@@ -144,14 +385,76 @@ impl TaskCache {
         self.cache.values().map(move |x| TaskCell { cell: &x, cache: self })
     }
 
+    /// Gives an Iterator over all tasks in the cache matching `filter`.
+    /// This allows running bulk operations over e.g. "all pending tasks in project X" without
+    /// re-querying taskwarrior.
+    pub fn query<'a>(&'a self, filter: &'a TaskFilter) -> impl Iterator<Item = TaskCell<'a>> {
+        self.iter().filter(move |cell| {
+            cell.borrow()
+                .map(|task| filter.matches(&task))
+                .unwrap_or(false)
+        })
+    }
+
     /// Gives the task with the corresponding uuid.
     pub fn get_ptr(&self, uuid: &Uuid) -> Option<TaskCell> {
         self.cache.get(uuid).map(|x| TaskCell { cell: &x, cache: self })
     }
 
-    /// Sets a new task into the cache. It will be marked as dirty and saved on the next `write()`.
-    pub fn set(&mut self, task: Task) {
-        self.cache.extend(once(task_to_entry(task)));
+    /// Sets a new task into the cache. It will be marked as dirty and saved on the next `write()`,
+    /// regardless of its content hash: since it has no prior baseline (`None`) yet, `write()`
+    /// always treats it as changed.
+    pub fn set(&mut self, task: Task) -> Result<()> {
+        let (uuid, entry) = task_to_entry(task)?;
+        {
+            let mut guard = entry.borrow_mut();
+            guard.1 = MutationState::Dirty;
+            guard.2 = None;
+        }
+        self.cache.extend(once((uuid, entry)));
+        self.maybe_autoflush()
+    }
+
+    /// Serializes the cache to `path` as zstd-compressed JSON, using `DEFAULT_ZSTD_LEVEL`.
+    /// Dirty tasks are persisted as dirty, so unsaved changes survive a reload via
+    /// `load_from_path`.
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.save_to_path_with_level(path, DEFAULT_ZSTD_LEVEL)
+    }
+
+    /// Like `save_to_path`, but with a configurable zstd compression `level`.
+    /// Uses `try_borrow` rather than `borrow`, since a cell may be borrowed elsewhere (e.g. a live
+    /// `TaskCellMut`) while this runs; such a cell is simply skipped from the snapshot instead of
+    /// panicking, and will be picked up by a later `save_to_path` once it is released.
+    pub fn save_to_path_with_level<P: AsRef<Path>>(&self, path: P, level: i32) -> Result<()> {
+        let file = File::create(path).map_err(|_| Ek::SerializeError)?;
+        let encoder = Encoder::new(file, level).map_err(|_| Ek::SerializeError)?;
+        let snapshot = self
+            .cache
+            .iter()
+            .filter_map(|(uuid, cell)| cell.try_borrow().ok().map(|entry| (*uuid, entry.clone())))
+            .collect::<HashMap<Uuid, (Task, MutationState, Option<TaskHash>)>>();
+        serde_json::to_writer(encoder.auto_finish(), &snapshot).map_err(|_| Ek::SerializeError)?;
+        Ok(())
+    }
+
+    /// Loads a cache previously written by `save_to_path`, preserving dirty flags so that
+    /// unsaved edits survive a restart. The returned cache is usable immediately; call
+    /// `refresh()` afterwards to reconcile it with taskwarrior.
+    pub fn load_from_path<P: AsRef<Path>>(path: P, ignore: Vec<TaskStatus>) -> Result<TaskCache> {
+        let file = File::open(path).map_err(|_| Ek::ReaderError)?;
+        let decoder = Decoder::new(file).map_err(|_| Ek::ReaderError)?;
+        let snapshot: HashMap<Uuid, (Task, MutationState, Option<TaskHash>)> =
+            serde_json::from_reader(decoder).map_err(|_| Ek::ParserError)?;
+        Ok(TaskCache {
+            cache: snapshot
+                .into_iter()
+                .map(|(uuid, entry)| (uuid, RefCell::new(entry)))
+                .collect(),
+            ignore,
+            autoflush: None,
+            dirty_since: Cell::new(None),
+        })
     }
 }
 
@@ -160,3 +463,229 @@ impl Default for TaskCache {
         Self::new(vec![TaskStatus::Completed, TaskStatus::Deleted])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use task::TaskBuilder;
+
+    fn sample_task(status: TaskStatus) -> Task {
+        TaskBuilder::default()
+            .status(status)
+            .uuid(Uuid::new_v4())
+            .description("test task".to_owned())
+            .build()
+            .expect("valid task fixture")
+    }
+
+    #[test]
+    fn filter_matches_everything_by_default() {
+        let filter = TaskFilter::new();
+        assert!(filter.matches(&sample_task(TaskStatus::Pending)));
+    }
+
+    #[test]
+    fn filter_matches_by_status() {
+        let filter = TaskFilter::new().filter_status(vec![TaskStatus::Pending]);
+        assert!(filter.matches(&sample_task(TaskStatus::Pending)));
+        assert!(!filter.matches(&sample_task(TaskStatus::Completed)));
+    }
+
+    #[test]
+    fn filter_combines_predicate_with_status() {
+        let filter = TaskFilter::new()
+            .filter_status(vec![TaskStatus::Pending])
+            .filter_fn(|_| false);
+        assert!(!filter.matches(&sample_task(TaskStatus::Pending)));
+    }
+
+    #[test]
+    fn should_update_treats_missing_baseline_as_changed() {
+        assert!(should_update([0u8; 32], None));
+    }
+
+    #[test]
+    fn should_update_skips_unchanged_hash() {
+        let hash = [7u8; 32];
+        assert!(!should_update(hash, Some(hash)));
+    }
+
+    #[test]
+    fn should_update_detects_changed_hash() {
+        let old = [1u8; 32];
+        let new = [2u8; 32];
+        assert!(should_update(new, Some(old)));
+    }
+
+    #[test]
+    fn should_autoflush_never_fires_with_nothing_dirty() {
+        let policy = AutoFlushPolicy::new().max_dirty(0).max_elapsed(Duration::from_secs(0));
+        assert!(!should_autoflush(policy, 0, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn should_autoflush_on_dirty_count_threshold() {
+        let policy = AutoFlushPolicy::new().max_dirty(3);
+        assert!(!should_autoflush(policy, 2, Duration::from_secs(0)));
+        assert!(should_autoflush(policy, 3, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn should_autoflush_on_elapsed_threshold() {
+        let policy = AutoFlushPolicy::new().max_elapsed(Duration::from_secs(30));
+        assert!(!should_autoflush(policy, 1, Duration::from_secs(10)));
+        assert!(should_autoflush(policy, 1, Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn should_autoflush_never_fires_without_any_threshold_configured() {
+        let policy = AutoFlushPolicy::new();
+        assert!(!should_autoflush(policy, 100, Duration::from_secs(1000)));
+    }
+}
+
+#[cfg(feature = "concurrent")]
+mod concurrent {
+    use super::{generate_query, Ek, MutationState, Result, TaskStatus};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    };
+    use task::Task;
+    use tw::{query, save};
+    use uuid::Uuid;
+
+    type Entry = Arc<RwLock<(Task, MutationState)>>;
+
+    fn task_to_entry(task: Task) -> (Uuid, Entry) {
+        (
+            *task.uuid(),
+            Arc::new(RwLock::new((task, MutationState::Clean))),
+        )
+    }
+
+    /// A thread-safe variant of `TaskCache`, backed by `RwLock` instead of `RefCell`.
+    /// Many readers can borrow tasks simultaneously, while writers are serialized. This makes it
+    /// `Send + Sync`, so it can live inside an `Arc` and be shared across worker threads running
+    /// bulk operations in parallel.
+    pub struct ConcurrentTaskCache {
+        cache: RwLock<HashMap<Uuid, Entry>>,
+        ignore: Vec<TaskStatus>,
+    }
+
+    /// A pointer to a Task in a `ConcurrentTaskCache`. Can be read-locked or write-locked.
+    /// The calls return an `Err` instead of panicking when the lock is held elsewhere or was
+    /// poisoned by a panicking thread.
+    pub struct ConcurrentTaskCell {
+        cell: Entry,
+    }
+
+    impl ConcurrentTaskCell {
+        /// Trys to lock the Task for reading.
+        pub fn read(&self) -> Result<RwLockReadGuard<(Task, MutationState)>> {
+            self.cell.try_read().map_err(|_| Ek::LockError.into())
+        }
+
+        /// Trys to lock the Task for writing. Marks it as dirty.
+        pub fn write(&self) -> Result<RwLockWriteGuard<(Task, MutationState)>> {
+            let mut guard = self.cell.try_write().map_err(|_| Ek::LockError)?;
+            guard.1 = MutationState::Dirty;
+            Ok(guard)
+        }
+    }
+
+    impl ConcurrentTaskCache {
+        /// Creates a new, empty ConcurrentTaskCache.
+        pub fn new(ignore: Vec<TaskStatus>) -> ConcurrentTaskCache {
+            ConcurrentTaskCache {
+                cache: RwLock::new(HashMap::new()),
+                ignore,
+            }
+        }
+
+        /// Gives tasks ignored by this ConcurrentTaskCache.
+        pub fn ignore(&self) -> &Vec<TaskStatus> {
+            &self.ignore
+        }
+
+        /// Will load all unignored tasks in the cache.
+        /// This will throw an error of kind DirtyCacheError, if there are unsaved changes.
+        pub fn load(&self) -> Result<()> {
+            let mut cache = self.cache.write().map_err(|_| Ek::LockError)?;
+            let has_dirty = cache.values().any(|entry| {
+                entry
+                    .read()
+                    .map(|(_, state)| *state == MutationState::Dirty)
+                    .unwrap_or(true)
+            });
+            if has_dirty {
+                bail!(Ek::DirtyCacheError);
+            }
+            cache.clear();
+            query(&generate_query(&self.ignore))
+                .map(|tasks| cache.extend(tasks.into_iter().map(task_to_entry)))
+        }
+
+        /// Gives the task with the corresponding uuid.
+        pub fn get_ptr(&self, uuid: &Uuid) -> Result<Option<ConcurrentTaskCell>> {
+            let cache = self.cache.read().map_err(|_| Ek::LockError)?;
+            Ok(cache
+                .get(uuid)
+                .map(|entry| ConcurrentTaskCell { cell: Arc::clone(entry) }))
+        }
+
+        /// Gives pointers to every task currently in the cache.
+        pub fn iter(&self) -> Result<Vec<ConcurrentTaskCell>> {
+            let cache = self.cache.read().map_err(|_| Ek::LockError)?;
+            Ok(cache
+                .values()
+                .map(|entry| ConcurrentTaskCell { cell: Arc::clone(entry) })
+                .collect())
+        }
+
+        /// Sets a new task into the cache. It will be marked as dirty and saved on the next
+        /// `write()`.
+        pub fn set(&self, task: Task) -> Result<()> {
+            let mut cache = self.cache.write().map_err(|_| Ek::LockError)?;
+            let (uuid, entry) = task_to_entry(task);
+            entry.write().map_err(|_| Ek::LockError)?.1 = MutationState::Dirty;
+            cache.insert(uuid, entry);
+            Ok(())
+        }
+
+        /// Saves all entries marked as dirty, in a single batched `tw::save` call, and marks
+        /// them clean again.
+        /// Uses `try_read`/`try_write` throughout, like `ConcurrentTaskCell`, so that a cell
+        /// borrowed elsewhere is simply left dirty for a later `write()` instead of deadlocking
+        /// or panicking on a poisoned lock.
+        pub fn write(&self) -> Result<()> {
+            let cache = self.cache.try_read().map_err(|_| Ek::LockError)?;
+            let dirty_entries = cache
+                .values()
+                .filter(|entry| {
+                    entry
+                        .try_read()
+                        .map(|(_, state)| *state == MutationState::Dirty)
+                        .unwrap_or(false)
+                })
+                .map(Arc::clone)
+                .collect::<Vec<_>>();
+            drop(cache);
+            if dirty_entries.is_empty() {
+                return Ok(());
+            }
+            let mut to_save = Vec::new();
+            for entry in &dirty_entries {
+                let mut guard = match entry.try_write() {
+                    Ok(guard) => guard,
+                    Err(_) => continue,
+                };
+                guard.1 = MutationState::Clean;
+                to_save.push(guard.0.clone());
+            }
+            save(to_save.iter())
+        }
+    }
+}
+#[cfg(feature = "concurrent")]
+pub use self::concurrent::{ConcurrentTaskCache, ConcurrentTaskCell};
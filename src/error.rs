@@ -33,5 +33,11 @@ error_chain!{
         DirtyCacheError {
             description("Tried to discard unsaved changes in TaskCache.")
         }
+        /// Error kind indicating that a lock on a concurrent TaskCache (or one of its entries)
+        /// could not be acquired, either because it is currently held by another thread or
+        /// because that thread panicked while holding it.
+        LockError {
+            description("Failed to acquire a lock on the concurrent TaskCache")
+        }
     }
 }